@@ -4,7 +4,7 @@ use tokio::time::delay_for;
 
 use stop_handle::stop_handle;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum TerminationReason {
     Manual,
 }
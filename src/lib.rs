@@ -1,12 +1,15 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+#[cfg(feature = "tokio")]
+use std::time::{Duration, Instant};
 
-use futures::channel::oneshot;
-use futures::future::{Fuse, FusedFuture, FutureExt};
-use futures::ready;
-use futures::task::{Context, Poll};
+use futures::future::{FusedFuture, FutureExt};
+use futures::stream::{FuturesUnordered, Stream};
+use futures::task::{Context, Poll, Waker};
 use pin_project_lite::pin_project;
 
 pub enum StopReason<T> {
@@ -50,10 +53,77 @@ impl<T> Clone for StopReason<T>
     }
 }
 
-pub struct StopHandle<T> {
-    inner: Arc<Mutex<Option<oneshot::Sender<T>>>>,
+struct State<T> {
+    reason: Option<T>,
+    lost: bool,
+    wakers: HashMap<u64, Waker>,
+    children: Vec<Weak<Shared<T>>>,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    handle_count: AtomicUsize,
+    next_waiter_id: AtomicU64,
 }
 
+impl<T> Shared<T> {
+    fn resolve(&self, reason: Option<T>) {
+        Self::resolve_locked(&mut self.state.lock().unwrap(), reason);
+    }
+
+    fn resolve_locked(state: &mut State<T>, reason: Option<T>) {
+        if state.reason.is_some() || state.lost {
+            return;
+        }
+        match reason {
+            Some(reason) => state.reason = Some(reason),
+            None => state.lost = true,
+        }
+        for (_, waker) in state.wakers.drain() {
+            waker.wake();
+        }
+    }
+
+    /// Resolves `self` with `HandleLost` and cascades the same fate down to
+    /// every live descendant, however deep. Unlike [`Shared::stop_cascade`]
+    /// this needs no `T: Clone`, since `HandleLost` carries no reason. The
+    /// cascade and `self`'s own resolution happen under one `state` lock, so
+    /// a concurrent `child()` can't slip a new child past the cascade.
+    fn lost_cascade(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.children.retain(|child| child.strong_count() > 0);
+        for child in state.children.iter() {
+            if let Some(child) = child.upgrade() {
+                child.lost_cascade();
+            }
+        }
+        Self::resolve_locked(&mut state, None);
+    }
+}
+
+impl<T> Shared<T>
+    where
+        T: Clone,
+{
+    /// Resolves `self` with `reason` and cascades the same reason down to
+    /// every live descendant, however deep. The cascade and `self`'s own
+    /// resolution happen under one `state` lock, so a concurrent `child()`
+    /// can't slip a new child past the cascade.
+    fn stop_cascade(&self, reason: T) {
+        let mut state = self.state.lock().unwrap();
+        state.children.retain(|child| child.strong_count() > 0);
+        for child in state.children.iter() {
+            if let Some(child) = child.upgrade() {
+                child.stop_cascade(reason.clone());
+            }
+        }
+        Self::resolve_locked(&mut state, Some(reason));
+    }
+}
+
+pub struct StopHandle<T> {
+    inner: Arc<Shared<T>>,
+}
 
 impl<T> fmt::Debug for StopHandle<T>
 {
@@ -64,66 +134,364 @@ impl<T> fmt::Debug for StopHandle<T>
 
 impl<T> Clone for StopHandle<T> {
     fn clone(&self) -> Self {
+        self.inner.handle_count.fetch_add(1, Ordering::SeqCst);
         StopHandle {
             inner: Arc::clone(&self.inner),
         }
     }
 }
 
-impl<T> StopHandle<T> {
+impl<T> Drop for StopHandle<T> {
+    fn drop(&mut self) {
+        if self.inner.handle_count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.lost_cascade();
+        }
+    }
+}
+
+impl<T> StopHandle<T>
+    where
+        T: Clone,
+{
     pub fn stop(&self, reason: T) {
-        if let Some(tx) = self.inner.lock().unwrap().take() {
-            let _ = tx.send(reason);
+        self.inner.stop_cascade(reason);
+    }
+
+    /// Creates a new handle/wait pair that is stopped automatically whenever
+    /// this handle (or any of its ancestors) is stopped, with a clone of the
+    /// same reason, or whenever this handle (or an ancestor) is dropped
+    /// without being stopped, cascading `HandleLost` the same way. If `self`
+    /// is already stopped or lost, the child is resolved immediately with
+    /// the same outcome instead of only being wired up for future cascades.
+    /// Stopping the child directly has no effect on `self`.
+    pub fn child(&self) -> (StopHandle<T>, StopWait<T>) {
+        let (child_handle, child_wait) = stop_handle();
+
+        let mut state = self.inner.state.lock().unwrap();
+        state.children.retain(|child| child.strong_count() > 0);
+
+        if let Some(reason) = &state.reason {
+            child_wait.inner.resolve(Some(reason.clone()));
+        } else if state.lost {
+            child_wait.inner.resolve(None);
         }
+
+        state.children.push(Arc::downgrade(&child_wait.inner));
+
+        (child_handle, child_wait)
     }
 }
 
-pin_project! {
-    pub struct StopWait<T> {
-        #[pin]
-        inner: Fuse<oneshot::Receiver<T>>,
+pub struct StopWait<T> {
+    inner: Arc<Shared<T>>,
+    waiter_id: u64,
+}
+
+impl<T> Clone for StopWait<T>
+    where
+        T: Clone,
+{
+    fn clone(&self) -> Self {
+        let waiter_id = self.inner.next_waiter_id.fetch_add(1, Ordering::SeqCst);
+        StopWait {
+            inner: Arc::clone(&self.inner),
+            waiter_id,
+        }
+    }
+}
+
+impl<T> Drop for StopWait<T> {
+    fn drop(&mut self) {
+        self.inner.state.lock().unwrap().wakers.remove(&self.waiter_id);
     }
 }
 
-impl<T> FusedFuture for StopWait<T> {
+impl<T> FusedFuture for StopWait<T>
+    where
+        T: Clone,
+{
     fn is_terminated(&self) -> bool {
-        self.inner.is_terminated()
+        let state = self.inner.state.lock().unwrap();
+        state.reason.is_some() || state.lost
     }
 }
 
-impl<T> Future for StopWait<T> {
+impl<T> Future for StopWait<T>
+    where
+        T: Clone,
+{
     type Output = StopReason<T>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let r = match ready!(Future::poll(self.project().inner, cx)) {
-            Err(_) => StopReason::HandleLost,
-            Ok(reason) => StopReason::Requested(reason),
-        };
-        Poll::Ready(r)
+        let mut state = self.inner.state.lock().unwrap();
+        if let Some(reason) = &state.reason {
+            return Poll::Ready(StopReason::Requested(reason.clone()));
+        }
+        if state.lost {
+            return Poll::Ready(StopReason::HandleLost);
+        }
+        state.wakers.insert(self.waiter_id, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T> StopHandle<T>
+    where
+        T: Clone + Send + 'static,
+{
+    /// Stops this handle after `delay` elapses, unless it is stopped
+    /// manually (or dropped) before then. Holds only a weak reference, so a
+    /// dropped handle still reports `HandleLost` promptly rather than waiting
+    /// out the delay.
+    pub fn stop_after(&self, delay: Duration, reason: T) {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            tokio::time::delay_for(delay).await;
+            if let Some(inner) = inner.upgrade() {
+                inner.stop_cascade(reason);
+            }
+        });
+    }
+
+    /// Stops this handle at the given `Instant`; see [`Self::stop_after`] for
+    /// the early-drop semantics.
+    pub fn stop_at(&self, when: Instant, reason: T) {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            tokio::time::delay_until(tokio::time::Instant::from_std(when)).await;
+            if let Some(inner) = inner.upgrade() {
+                inner.stop_cascade(reason);
+            }
+        });
     }
 }
 
 pub fn stop_handle<T>() -> (StopHandle<T>, StopWait<T>) {
-    let (tx, rx) = oneshot::channel();
+    let inner = Arc::new(Shared {
+        state: Mutex::new(State {
+            reason: None,
+            lost: false,
+            wakers: HashMap::new(),
+            children: Vec::new(),
+        }),
+        handle_count: AtomicUsize::new(1),
+        next_waiter_id: AtomicU64::new(1),
+    });
+
     let stop_handle = StopHandle {
-        inner: Arc::new(Mutex::new(Some(tx))),
+        inner: Arc::clone(&inner),
     };
 
-    let stop_wait = StopWait { inner: rx.fuse() };
+    let stop_wait = StopWait {
+        inner,
+        waiter_id: 0,
+    };
 
     (stop_handle, stop_wait)
 }
 
+impl<T> StopWait<T> {
+    /// Adapts `stream` so that it yields items until this handle is stopped,
+    /// then ends (returning `None`). The [`StopReason`] can be retrieved
+    /// afterwards via [`TakeUntilStop::into_reason`].
+    pub fn take_until<S>(self, stream: S) -> TakeUntilStop<S, T>
+        where
+            S: Stream,
+    {
+        TakeUntilStop {
+            inner: stream,
+            stop_wait: self,
+            reason: None,
+        }
+    }
+}
+
+pin_project! {
+    /// A [`Stream`] adapter returned by [`StopWait::take_until`].
+    pub struct TakeUntilStop<S, T> {
+        #[pin]
+        inner: S,
+        #[pin]
+        stop_wait: StopWait<T>,
+        reason: Option<StopReason<T>>,
+    }
+}
+
+impl<S, T> TakeUntilStop<S, T> {
+    /// Returns the reason the stream stopped, if it has stopped already.
+    pub fn into_reason(self) -> Option<StopReason<T>> {
+        self.reason
+    }
+}
+
+impl<S, T> Stream for TakeUntilStop<S, T>
+    where
+        S: Stream,
+        T: Clone,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+
+        if this.reason.is_some() {
+            return Poll::Ready(None);
+        }
+
+        if let Poll::Ready(reason) = this.stop_wait.poll(cx) {
+            *this.reason = Some(reason);
+            return Poll::Ready(None);
+        }
+
+        this.inner.poll_next(cx)
+    }
+}
+
+/// Error returned by [`Stoppable`] when the wrapped future was cancelled
+/// before it could resolve on its own.
+pub struct Stopped<T>(pub StopReason<T>);
+
+impl<T> fmt::Display for Stopped<T>
+    where
+        T: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stopped: {}", self.0)
+    }
+}
+
+impl<T> fmt::Debug for Stopped<T>
+    where
+        T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Stopped({:?})", self.0)
+    }
+}
+
+pin_project! {
+    /// A future that resolves with the wrapped `Fut`'s output, unless the
+    /// associated [`StopHandle`] fires first, in which case it resolves
+    /// immediately with `Err(Stopped(reason))`.
+    pub struct Stoppable<Fut, T> {
+        #[pin]
+        inner: Fut,
+        #[pin]
+        stop_wait: StopWait<T>,
+    }
+}
+
+impl<Fut, T> Future for Stoppable<Fut, T>
+    where
+        Fut: Future,
+        T: Clone,
+{
+    type Output = Result<Fut::Output, Stopped<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        if let Poll::Ready(reason) = this.stop_wait.poll(cx) {
+            return Poll::Ready(Err(Stopped(reason)));
+        }
+
+        this.inner.poll(cx).map(Ok)
+    }
+}
+
+/// Wraps `future` so that it is cancelled as soon as the returned
+/// [`StopHandle`] is stopped, much like `futures::future::abortable`.
+pub fn stoppable<Fut, T>(future: Fut) -> (Stoppable<Fut, T>, StopHandle<T>)
+    where
+        Fut: Future,
+{
+    let (stop_handle, stop_wait) = stop_handle();
+
+    let stoppable = Stoppable {
+        inner: future,
+        stop_wait,
+    };
+
+    (stoppable, stop_handle)
+}
+
+/// A single entry in a [`StopGroup`]'s wait set: a `StopWait` tagged with
+/// the key it was inserted under.
+type StopFuture<K, T> = Pin<Box<dyn Future<Output = (K, StopReason<T>)> + Send>>;
+
+/// Aggregates a number of [`StopHandle`]/[`StopWait`] pairs and reports
+/// which one fired first, tagged with the key it was inserted under.
+pub struct StopGroup<K, T> {
+    waiters: FuturesUnordered<StopFuture<K, T>>,
+}
+
+impl<K, T> StopGroup<K, T> {
+    pub fn new() -> Self {
+        StopGroup {
+            waiters: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl<K, T> Default for StopGroup<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, T> StopGroup<K, T>
+    where
+        K: Send + 'static,
+        T: Clone + Send + 'static,
+{
+    /// Creates a new handle under `key` and starts tracking its `StopWait`.
+    pub fn insert(&mut self, key: K) -> StopHandle<T> {
+        let (handle, wait) = stop_handle();
+        self.waiters.push(Box::pin(wait.map(move |reason| (key, reason))));
+
+        handle
+    }
+
+    /// Resolves with the key and reason of the first handle in the group to
+    /// be stopped, leaving the rest pending.
+    ///
+    /// The group must be non-empty: `wait_any` holds `&mut self` for the
+    /// whole await, so entries can only be added between calls, never while
+    /// one is pending.
+    pub fn wait_any(&mut self) -> WaitAny<'_, K, T> {
+        WaitAny { group: self }
+    }
+}
+
+pub struct WaitAny<'a, K, T> {
+    group: &'a mut StopGroup<K, T>,
+}
+
+impl<'a, K, T> Future for WaitAny<'a, K, T> {
+    type Output = (K, StopReason<T>);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.group.waiters).poll_next(cx) {
+            Poll::Ready(Some(item)) => Poll::Ready(item),
+            Poll::Ready(None) => Poll::Pending,
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::Duration;
 
+    use futures::stream::{self, StreamExt};
     use matches::assert_matches;
     use tokio::time::delay_for;
 
     use super::*;
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub enum TerminationReason {
         Manual,
     }
@@ -140,4 +508,205 @@ mod tests {
         let res = stop_wait.await;
         assert_matches!(res, StopReason::Requested(TerminationReason::Manual));
     }
+
+    #[tokio::test]
+    async fn test_stoppable() {
+        let (fut, stop_handle) = stoppable(async {
+            delay_for(Duration::from_secs(10)).await;
+            42
+        });
+
+        tokio::spawn(async move {
+            delay_for(Duration::from_millis(50)).await;
+            stop_handle.stop(TerminationReason::Manual);
+        });
+
+        let res = fut.await;
+        assert_matches!(res, Err(Stopped(StopReason::Requested(TerminationReason::Manual))));
+    }
+
+    #[tokio::test]
+    async fn test_multiple_waiters() {
+        let (stop_handle, stop_wait) = stop_handle();
+        let stop_wait_2 = stop_wait.clone();
+
+        tokio::spawn(async move {
+            delay_for(Duration::from_millis(50)).await;
+            stop_handle.stop(TerminationReason::Manual);
+        });
+
+        let (res_1, res_2) = futures::future::join(stop_wait, stop_wait_2).await;
+        assert_matches!(res_1, StopReason::Requested(TerminationReason::Manual));
+        assert_matches!(res_2, StopReason::Requested(TerminationReason::Manual));
+    }
+
+    #[tokio::test]
+    async fn test_handle_lost_delivered_to_all_waiters() {
+        let (stop_handle, stop_wait) = stop_handle::<TerminationReason>();
+        let stop_wait_2 = stop_wait.clone();
+
+        drop(stop_handle);
+
+        let (res_1, res_2) = futures::future::join(stop_wait, stop_wait_2).await;
+        assert_matches!(res_1, StopReason::HandleLost);
+        assert_matches!(res_2, StopReason::HandleLost);
+    }
+
+    #[tokio::test]
+    async fn test_child_stopped_with_parent() {
+        let (parent_handle, _parent_wait) = stop_handle();
+        let (_child_handle, child_wait) = parent_handle.child();
+
+        parent_handle.stop(TerminationReason::Manual);
+
+        let res = child_wait.await;
+        assert_matches!(res, StopReason::Requested(TerminationReason::Manual));
+    }
+
+    #[tokio::test]
+    async fn test_grandchild_stopped_with_root() {
+        let (root_handle, _root_wait) = stop_handle();
+        let (child_handle, _child_wait) = root_handle.child();
+        let (_grandchild_handle, grandchild_wait) = child_handle.child();
+
+        root_handle.stop(TerminationReason::Manual);
+
+        let res = grandchild_wait.await;
+        assert_matches!(res, StopReason::Requested(TerminationReason::Manual));
+    }
+
+    #[tokio::test]
+    async fn test_grandchild_lost_when_root_dropped() {
+        let (root_handle, _root_wait) = stop_handle::<TerminationReason>();
+        let (child_handle, _child_wait) = root_handle.child();
+        let (_grandchild_handle, grandchild_wait) = child_handle.child();
+
+        drop(root_handle);
+
+        let res = grandchild_wait.await;
+        assert_matches!(res, StopReason::HandleLost);
+    }
+
+    #[tokio::test]
+    async fn test_child_of_already_stopped_parent_resolves_immediately() {
+        let (parent_handle, _parent_wait) = stop_handle();
+        parent_handle.stop(TerminationReason::Manual);
+
+        let (_child_handle, child_wait) = parent_handle.child();
+
+        let res = child_wait.await;
+        assert_matches!(res, StopReason::Requested(TerminationReason::Manual));
+    }
+
+    #[tokio::test]
+    async fn test_child_of_already_lost_parent_resolves_immediately() {
+        let (root_handle, _root_wait) = stop_handle::<TerminationReason>();
+        let (child_handle, _child_wait) = root_handle.child();
+
+        // Drops `root_handle`, cascading `HandleLost` down to `child_handle`'s
+        // `Shared` even though `child_handle` itself is still alive.
+        drop(root_handle);
+
+        let (_grandchild_handle, grandchild_wait) = child_handle.child();
+
+        let res = grandchild_wait.await;
+        assert_matches!(res, StopReason::HandleLost);
+    }
+
+    #[test]
+    fn test_dead_children_are_pruned() {
+        let (root_handle, _root_wait) = stop_handle::<TerminationReason>();
+
+        for _ in 0..10 {
+            let (_child_handle, _child_wait) = root_handle.child();
+        }
+
+        assert_eq!(root_handle.inner.state.lock().unwrap().children.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_child_stop_does_not_affect_parent() {
+        let (parent_handle, parent_wait) = stop_handle();
+        let (child_handle, _child_wait) = parent_handle.child();
+
+        child_handle.stop(TerminationReason::Manual);
+
+        assert!(!parent_wait.is_terminated());
+    }
+
+    #[tokio::test]
+    async fn test_stop_group_wait_any() {
+        let mut group = StopGroup::new();
+        let handle_a = group.insert("a");
+        let _handle_b = group.insert("b");
+
+        handle_a.stop(TerminationReason::Manual);
+
+        let (key, reason) = group.wait_any().await;
+        assert_eq!(key, "a");
+        assert_matches!(reason, StopReason::Requested(TerminationReason::Manual));
+    }
+
+    #[test]
+    fn test_waker_storage_bounded_per_waiter() {
+        use futures::task::noop_waker_ref;
+
+        let (_stop_handle, mut stop_wait) = stop_handle::<TerminationReason>();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        for _ in 0..10 {
+            assert!(Pin::new(&mut stop_wait).poll(&mut cx).is_pending());
+        }
+
+        assert_eq!(stop_wait.inner.state.lock().unwrap().wakers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_take_until_stop() {
+        let (stop_handle, stop_wait) = stop_handle();
+
+        let mut stopped_stream = stop_wait.take_until(stream::repeat(1u32));
+
+        assert_eq!(stopped_stream.next().await, Some(1));
+        assert_eq!(stopped_stream.next().await, Some(1));
+
+        stop_handle.stop(TerminationReason::Manual);
+
+        assert_eq!(stopped_stream.next().await, None);
+        assert_matches!(
+            stopped_stream.into_reason(),
+            Some(StopReason::Requested(TerminationReason::Manual))
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_stop_after() {
+        let (stop_handle, stop_wait) = stop_handle();
+
+        stop_handle.stop_after(Duration::from_millis(50), TerminationReason::Manual);
+
+        let res = stop_wait.await;
+        assert_matches!(res, StopReason::Requested(TerminationReason::Manual));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_stop_after_does_not_outlive_dropped_handle() {
+        let (stop_handle, stop_wait) = stop_handle();
+
+        stop_handle.stop_after(Duration::from_secs(10), TerminationReason::Manual);
+        drop(stop_handle);
+
+        let res = tokio::time::timeout(Duration::from_millis(50), stop_wait).await;
+        assert_matches!(res, Ok(StopReason::HandleLost));
+    }
+
+    #[tokio::test]
+    async fn test_stoppable_completes_normally() {
+        let (fut, _stop_handle) = stoppable::<_, TerminationReason>(async { 42 });
+
+        let res = fut.await;
+        assert_matches!(res, Ok(42));
+    }
 }